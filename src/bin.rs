@@ -1,15 +1,33 @@
 use futures::stream::StreamExt;
 use std::{
+    collections::HashMap,
     env,
-    fs::create_dir_all,
+    fs::{self, create_dir_all},
     path::{Path, PathBuf},
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
     thread,
     time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context, Error, Result};
-use chan_downloader::{get_image_links, get_page_content, get_thread_infos, save_image};
+use chan_downloader::{
+    get_board_threads,
+    get_image_links,
+    get_image_links_from_api,
+    get_page_content,
+    get_thread_info,
+    hash_file_md5,
+    load_ledger,
+    parse_size,
+    save_image,
+    save_ledger,
+    DownloadProgress,
+    LedgerEntry,
+    LinkFilter,
+};
 use clap::{
     crate_authors,
     crate_description,
@@ -18,31 +36,40 @@ use clap::{
     AppSettings,
     Arg,
     ArgAction,
+    ArgMatches,
     ColorChoice,
     Command,
     ValueHint,
 };
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use log::{error, info};
-use once_cell::sync::Lazy;
 use reqwest::Client;
 
-static DOWNLOADED_FILES: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
-
 fn main() -> Result<()> {
     env_logger::init();
     let matches = build_app().get_matches();
 
-    let thread = matches
-        .get_one::<String>("thread")
-        .context("failed to get 'thread' value")?;
     let output = matches
         .get_one::<String>("output")
         .map_or_else(|| String::from("downloads"), Clone::clone);
+    let concurrent = matches.get_one::<usize>("concurrent").unwrap_or(&2_usize);
+    let max_retries = matches.get_one::<u32>("max_retries").unwrap_or(&3_u32);
+    let retry_wait = Duration::from_secs(*matches.get_one::<u64>("retry_wait").unwrap_or(&5_u64));
+    let filter = build_link_filter(&matches)?;
+
+    if let Some(board) = matches.get_one::<String>("board") {
+        let board_name = parse_board_name(board)?;
+        let board_concurrent = matches.get_one::<usize>("board_concurrent").unwrap_or(&3_usize);
+        info!("Crawling board {} to {}", board_name, output);
+        return download_board(&board_name, &output, *concurrent, *board_concurrent, *max_retries, retry_wait, filter);
+    }
+
+    let thread = matches
+        .get_one::<String>("thread")
+        .context("failed to get 'thread' value")?;
     let reload = matches.contains_id("reload");
     let interval = matches.get_one::<u64>("interval").unwrap_or(&5_u64);
     let limit = matches.get_one::<u64>("limit").unwrap_or(&120_u64);
-    let concurrent = matches.get_one::<usize>("concurrent").unwrap_or(&2_usize);
 
     info!("Downloading images from {} to {}", thread, output);
 
@@ -57,7 +84,7 @@ fn main() -> Result<()> {
     };
     loop {
         let load_start = Instant::now();
-        explore_thread(thread, &directory, *concurrent).unwrap();
+        explore_thread(thread, &directory, *concurrent, *max_retries, retry_wait, &filter).unwrap();
         let runtime = start.elapsed();
         let load_runtime = load_start.elapsed();
         if runtime > limit_time {
@@ -74,82 +101,199 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn mark_as_downloaded(file: &str) -> Result<&str, &str> {
-    let mut db = DOWNLOADED_FILES
-        .lock()
-        .map_err(|_| "Failed to acquire MutexGuard")?;
-    db.push(file.to_string());
+/// Builds a [`LinkFilter`] from the `--only`/`--exclude`/`--min-size`/
+/// `--max-size`/`--min-width`/`--min-height` flags.
+fn build_link_filter(matches: &ArgMatches) -> Result<LinkFilter> {
+    let parse_extensions =
+        |value: &String| value.split(',').map(|ext| ext.trim().trim_start_matches('.').to_lowercase()).collect();
+
+    Ok(LinkFilter {
+        only_extensions:    matches.get_one::<String>("only").map(parse_extensions),
+        exclude_extensions: matches.get_one::<String>("exclude").map(parse_extensions),
+        min_size:           matches.get_one::<String>("min_size").map(|value| parse_size(value)).transpose()?,
+        max_size:           matches.get_one::<String>("max_size").map(|value| parse_size(value)).transpose()?,
+        min_width:          matches.get_one::<u32>("min_width").copied(),
+        min_height:         matches.get_one::<u32>("min_height").copied(),
+    })
+}
 
-    Ok(file)
+/// Records a completed download in the ledger and flushes it to disk right
+/// away, so an interrupted run keeps most of its progress.
+fn record_in_ledger(ledger: &Mutex<HashMap<String, LedgerEntry>>, directory: &Path, image_path: &str, md5: Option<String>) {
+    let size = fs::metadata(image_path).map(|metadata| metadata.len()).unwrap_or(0);
+    let mut db = ledger.lock().unwrap();
+    db.insert(image_path.to_string(), LedgerEntry { size, md5 });
+    if let Err(err) = save_ledger(directory, &db) {
+        error!("Failed to persist download ledger: {}", err);
+    }
 }
 
 #[tokio::main]
-async fn explore_thread(thread_link: &str, directory: &Path, concurrent: usize) -> Result<(), Error> {
+async fn explore_thread(
+    thread_link: &str,
+    directory: &Path,
+    concurrent: usize,
+    max_retries: u32,
+    retry_wait: Duration,
+    filter: &LinkFilter,
+) -> Result<(), Error> {
     let start = Instant::now();
     let client = Client::builder().user_agent("reqwest").build()?;
 
-    match get_page_content(thread_link, &client).await {
-        Ok(page_string) => {
+    // 4plebs has no JSON API equivalent to 4chan's, so it still needs the
+    // regex-over-HTML path; everything else goes through the API.
+    let links_result = if thread_link.contains("4plebs") {
+        get_page_content(thread_link, &client)
+            .await
+            .map(|page_string| get_image_links(page_string.as_str()))
+    } else {
+        let thread = get_thread_info(thread_link);
+        get_image_links_from_api(&thread.board, thread.id, &client).await
+    };
+
+    match links_result {
+        Ok(links_vec) => {
             info!("Loaded content from {}", thread_link);
 
-            let links_vec = get_image_links(page_string.as_str());
-            let pb = ProgressBar::new(links_vec.len() as u64);
+            let links_vec: Vec<_> = links_vec
+                .into_iter()
+                .filter(|link| {
+                    let keep = filter.matches(link);
+                    if !keep {
+                        info!("Filtered out {} (doesn't match --only/--exclude/size/dimension filters)", link.name);
+                    }
+                    keep
+                })
+                .collect();
 
-            pb.set_style(
+            let multi = MultiProgress::new();
+
+            let total_bytes: u64 = links_vec.iter().filter_map(|link| link.fsize).sum();
+            let total_pb = multi.add(ProgressBar::new(total_bytes));
+            total_pb.set_style(
                 ProgressStyle::default_bar()
                     .template(
                         "{spinner:.green.bold} [{elapsed_precise}] [{bar:40.cyan.bold/blue}] \
-                         {pos}/{len} {msg} ({eta})",
+                         {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
                     )
                     .context("failed to build progress bar")?
                     .progress_chars("#>-"),
             );
-            pb.tick();
+            total_pb.tick();
+
+            let item_style = ProgressStyle::default_bar()
+                .template("  {msg:.dim} [{bar:30.cyan/blue}] {bytes}/{total_bytes}")
+                .context("failed to build progress bar")?
+                .progress_chars("#>-");
+
+            let ledger = Mutex::new(load_ledger(directory).unwrap_or_default());
+            let fetched_count = AtomicUsize::new(0);
+            let skipped_count = AtomicUsize::new(0);
 
             let fetches = futures::stream::iter(links_vec.into_iter().map(|link| {
                 let client = &client;
-                let pb = &pb;
+                let multi = multi.clone();
+                let total_pb = &total_pb;
+                let item_style = item_style.clone();
+                let ledger = &ledger;
+                let fetched_count = &fetched_count;
+                let skipped_count = &skipped_count;
                 async move {
-                    let img_path = directory.join(link.name);
+                    let img_path = directory.join(&link.name);
                     let image_path = img_path.to_str().unwrap();
-                    let has_been_downloaded = async {
-                        let db = DOWNLOADED_FILES
-                            .lock()
-                            .map_err(|_| String::from("Failed to acquire MutexGuard"))
-                            .unwrap();
-                        db.contains(&String::from(image_path))
-                    }
-                    .await;
 
-                    if has_been_downloaded {
+                    // Trust the ledger only if it recorded the same digest the API
+                    // just reported and the file on disk is still the size we wrote -
+                    // a cheap stat, not a re-hash. That way a resumed run over a
+                    // ledger full of thousands of files doesn't re-hash all of them,
+                    // but a file truncated/corrupted between runs still gets caught
+                    // and re-downloaded instead of trusted forever.
+                    let ledger_entry = ledger.lock().unwrap().get(image_path).cloned();
+                    let trusted_by_ledger = ledger_entry.as_ref().is_some_and(|entry| {
+                        link.md5.is_some()
+                            && entry.md5 == link.md5
+                            && fs::metadata(&img_path).map(|meta| meta.len() == entry.size).unwrap_or(false)
+                    });
+
+                    if trusted_by_ledger {
                         info!("Image {} previously downloaded. Skipped", img_path.display());
-                    } else if !img_path.exists() {
-                        match save_image(format!("https:{}", link.url).as_str(), image_path, client).await
-                        {
-                            Ok(path) => {
-                                info!("Saved image to {}", &path);
-                                let result = mark_as_downloaded(&path).unwrap();
-                                info!("{} added to downloaded files", result);
-                            },
-                            Err(err) => {
-                                error!("Couldn't save image {}", image_path);
-                                eprintln!("Error: {}", err);
-                            },
-                        }
+                        total_pb.inc(link.fsize.unwrap_or(0));
+                        skipped_count.fetch_add(1, Ordering::Relaxed);
                     } else {
-                        info!("Image {} already exists. Skipped", img_path.display());
-                        let result = mark_as_downloaded(image_path).unwrap();
-                        info!("{} added to downloaded files", result);
+                        let already_exists_and_valid = img_path.exists()
+                            && link.md5.as_deref().is_none_or(|expected| {
+                                hash_file_md5(&img_path)
+                                    .map(|digest| digest == expected)
+                                    .unwrap_or(false)
+                            });
+
+                        if !already_exists_and_valid {
+                            if img_path.exists() {
+                                info!("Image {} exists but failed checksum, re-downloading", img_path.display());
+                            }
+
+                            let item_pb = multi.add(ProgressBar::new(link.fsize.unwrap_or(0)));
+                            item_pb.set_style(item_style);
+                            item_pb.set_message(link.name.clone());
+                            let report_pb = item_pb.clone();
+                            let mut item_bytes_reported: u64 = 0;
+                            let report = move |event: DownloadProgress| match event {
+                                DownloadProgress::Reset => {
+                                    report_pb.set_position(0);
+                                    total_pb.set_position(total_pb.position().saturating_sub(item_bytes_reported));
+                                    item_bytes_reported = 0;
+                                },
+                                DownloadProgress::Length(length) => report_pb.set_length(length),
+                                DownloadProgress::Chunk(read) => {
+                                    report_pb.inc(read as u64);
+                                    total_pb.inc(read as u64);
+                                    item_bytes_reported += read as u64;
+                                },
+                            };
+
+                            match save_image(
+                                format!("https:{}", link.url).as_str(),
+                                image_path,
+                                client,
+                                link.md5.as_deref(),
+                                max_retries,
+                                retry_wait,
+                                report,
+                            )
+                            .await
+                            {
+                                Ok(path) => {
+                                    info!("Saved image to {}", &path);
+                                    item_pb.finish_and_clear();
+                                    record_in_ledger(ledger, directory, image_path, link.md5);
+                                    fetched_count.fetch_add(1, Ordering::Relaxed);
+                                },
+                                Err(err) => {
+                                    item_pb.abandon();
+                                    error!("Couldn't save image {}", image_path);
+                                    eprintln!("Error: {}", err);
+                                },
+                            }
+                        } else {
+                            info!("Image {} already exists. Skipped", img_path.display());
+                            total_pb.inc(link.fsize.unwrap_or(0));
+                            record_in_ledger(ledger, directory, image_path, link.md5);
+                            skipped_count.fetch_add(1, Ordering::Relaxed);
+                        }
                     }
-                    pb.inc(1);
                 }
             }))
             .buffer_unordered(concurrent)
             .collect::<Vec<()>>();
             fetches.await;
 
-            pb.finish_with_message("Done");
-            info!("Done in {:?}", start.elapsed());
+            total_pb.finish_with_message("Done");
+            info!(
+                "Done in {:?} ({} newly fetched, {} already present)",
+                start.elapsed(),
+                fetched_count.load(Ordering::Relaxed),
+                skipped_count.load(Ordering::Relaxed)
+            );
         },
         Err(e) => {
             error!("Failed to get content from {}", thread_link);
@@ -161,13 +305,24 @@ async fn explore_thread(thread_link: &str, directory: &Path, concurrent: usize)
     Ok(())
 }
 
+/// Extracts the board code (e.g. `wg`) from a board URL such as
+/// `https://boards.4chan.org/wg/`, mirroring the `url.split('/')` layout
+/// `chan_downloader::get_thread_info` uses for thread links.
+fn parse_board_name(url: &str) -> Result<String> {
+    url.split('/')
+        .nth(3)
+        .filter(|segment| !segment.is_empty())
+        .map(String::from)
+        .ok_or_else(|| anyhow!("couldn't parse a board name out of '{}'", url))
+}
+
 fn create_directory(thread_link: &str, output: &str) -> Result<PathBuf> {
     let workpath = env::current_dir()?;
     info!("Working from {}", workpath.display());
 
-    let (board_name, thread_id) = get_thread_infos(thread_link);
+    let thread = get_thread_info(thread_link);
 
-    let directory = workpath.join(output).join(board_name).join(thread_id);
+    let directory = workpath.join(output).join(&thread.board).join(thread.id.to_string());
     if !directory.exists() {
         match create_dir_all(&directory) {
             Ok(_) => {
@@ -185,6 +340,68 @@ fn create_directory(thread_link: &str, output: &str) -> Result<PathBuf> {
     Ok(directory)
 }
 
+#[tokio::main]
+async fn list_board_threads(board: &str, client: &Client) -> Result<Vec<u64>, Error> {
+    let thread_ids = get_board_threads(board, client).await?;
+    Ok(thread_ids)
+}
+
+/// Crawls every live thread on a board, running the same `explore_thread`
+/// loop used for a single thread over each one. Board-level concurrency
+/// (how many threads are crawled at once) is bounded separately from
+/// `concurrent` (how many images are fetched at once within a thread), so a
+/// large board doesn't open thousands of sockets simultaneously.
+fn download_board(
+    board: &str,
+    output: &str,
+    concurrent: usize,
+    board_concurrent: usize,
+    max_retries: u32,
+    retry_wait: Duration,
+    filter: LinkFilter,
+) -> Result<()> {
+    let start = Instant::now();
+    let client = Client::builder().user_agent("reqwest").build()?;
+
+    let thread_ids = list_board_threads(board, &client)?;
+    info!("Found {} threads on board {}", thread_ids.len(), board);
+
+    for chunk in thread_ids.chunks(board_concurrent) {
+        let handles: Vec<_> = chunk
+            .iter()
+            .map(|&id| {
+                let board = board.to_string();
+                let output = output.to_string();
+                let filter = filter.clone();
+                thread::spawn(move || {
+                    let thread_link = format!("https://boards.4chan.org/{}/thread/{}", board, id);
+                    match create_directory(&thread_link, &output) {
+                        Ok(directory) => {
+                            if let Err(err) = explore_thread(
+                                &thread_link,
+                                &directory,
+                                concurrent,
+                                max_retries,
+                                retry_wait,
+                                &filter,
+                            ) {
+                                error!("Failed to explore thread {}: {}", thread_link, err);
+                            }
+                        },
+                        Err(err) => error!("Failed to create directory for thread {}: {}", thread_link, err),
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+
+    info!("Done crawling board {} in {:?}", board, start.elapsed());
+    Ok(())
+}
+
 /// Build the command-line application
 fn build_app() -> Command<'static> {
     Command::new("chan-downloader")
@@ -204,12 +421,31 @@ fn build_app() -> Command<'static> {
             Arg::new("thread")
                 .short('t')
                 .long("thread")
-                .required(true)
+                .required_unless_present("board")
+                .conflicts_with("board")
                 .takes_value(true)
                 .value_name("URL")
                 .value_parser(clap::builder::NonEmptyStringValueParser::new())
                 .help("URL of the thread"),
         )
+        .arg(
+            Arg::new("board")
+                .short('b')
+                .long("board")
+                .conflicts_with("thread")
+                .takes_value(true)
+                .value_name("URL")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("URL of the board (downloads every live thread on it)"),
+        )
+        .arg(
+            Arg::new("board_concurrent")
+                .long("board-concurrent")
+                .takes_value(true)
+                .value_name("NUM-THREADS")
+                .value_parser(value_parser!(usize))
+                .help("Number of threads crawled concurrently when downloading a board (Default is 3)"),
+        )
         .arg(
             Arg::new("output")
                 .short('o')
@@ -260,6 +496,68 @@ fn build_app() -> Command<'static> {
                 .value_parser(value_parser!(usize))
                 .help("Number of concurrent requests (Default is 2)"),
         )
+        .arg(
+            Arg::new("retry_wait")
+                .long("retry-wait")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .value_parser(value_parser!(u64))
+                .help("Initial delay between retries, doubled on each failure (in seconds. Default is 5)"),
+        )
+        .arg(
+            Arg::new("max_retries")
+                .long("max-retries")
+                .takes_value(true)
+                .value_name("NUM-RETRIES")
+                .value_parser(value_parser!(u32))
+                .help("Number of times to retry a failed download before giving up (Default is 3)"),
+        )
+        .arg(
+            Arg::new("only")
+                .long("only")
+                .takes_value(true)
+                .value_name("EXT,EXT,...")
+                .conflicts_with("exclude")
+                .help("Only download files with one of these extensions (e.g. 'webm,gif')"),
+        )
+        .arg(
+            Arg::new("exclude")
+                .long("exclude")
+                .takes_value(true)
+                .value_name("EXT,EXT,...")
+                .conflicts_with("only")
+                .help("Skip files with one of these extensions"),
+        )
+        .arg(
+            Arg::new("min_size")
+                .long("min-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Skip files smaller than this (accepts e.g. '500K', '4M')"),
+        )
+        .arg(
+            Arg::new("max_size")
+                .long("max-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Skip files larger than this (accepts e.g. '500K', '4M')"),
+        )
+        .arg(
+            Arg::new("min_width")
+                .long("min-width")
+                .takes_value(true)
+                .value_name("PIXELS")
+                .value_parser(value_parser!(u32))
+                .help("Skip images narrower than this"),
+        )
+        .arg(
+            Arg::new("min_height")
+                .long("min-height")
+                .takes_value(true)
+                .value_name("PIXELS")
+                .value_parser(value_parser!(u32))
+                .help("Skip images shorter than this"),
+        )
         .arg(
             Arg::new("verbose")
                 .short('v')
@@ -275,3 +573,14 @@ fn build_app() -> Command<'static> {
 fn verify_app() {
     build_app().debug_assert();
 }
+
+#[test]
+fn it_parses_board_name_from_url() {
+    assert_eq!(parse_board_name("https://boards.4chan.org/wg/").unwrap(), "wg");
+    assert_eq!(parse_board_name("https://boards.4chan.org/wg/catalog").unwrap(), "wg");
+}
+
+#[test]
+fn it_rejects_a_board_name_with_no_slashes() {
+    assert!(parse_board_name("wg").is_err());
+}
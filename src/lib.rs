@@ -3,13 +3,71 @@
 //! `chan_downloader` is a collection of utilities to
 //! download images/webms from a 4chan thread
 
+use anyhow::{anyhow, Context, Result};
+use futures::StreamExt;
 use log::info;
-use reqwest::{Client, Error};
+use md5::{Digest, Md5};
+use reqwest::{Client, Error, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    fs::File,
-    io::{self, Cursor},
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    time::Duration,
 };
 
+/// Reports progress of a single [`save_image`] download: `Reset` at the
+/// start of each attempt (including retries, so a partially-streamed
+/// attempt doesn't leave stray bytes counted against the total), then the
+/// total size once known from `Content-Length`, then the size of each
+/// chunk as it arrives off the wire.
+#[derive(Debug, Clone, Copy)]
+pub enum DownloadProgress {
+    Reset,
+    Length(u64),
+    Chunk(usize),
+}
+
+/// Name of the resumable download ledger written into each output
+/// directory.
+pub const LEDGER_FILE_NAME: &str = ".chan-downloader.json";
+
+/// A single entry in the download ledger, keyed by the absolute path of a
+/// downloaded image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub size: u64,
+    pub md5:  Option<String>,
+}
+
+/// Loads the resumable download ledger for a directory. Returns an empty
+/// ledger if none has been persisted yet.
+pub fn load_ledger(directory: &Path) -> Result<HashMap<String, LedgerEntry>> {
+    let ledger_path = directory.join(LEDGER_FILE_NAME);
+    if !ledger_path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = fs::read_to_string(&ledger_path).with_context(|| format!("failed to read {}", ledger_path.display()))?;
+    serde_json::from_str(&content).with_context(|| format!("failed to parse {}", ledger_path.display()))
+}
+
+/// Persists the resumable download ledger for a directory.
+pub fn save_ledger(directory: &Path, ledger: &HashMap<String, LedgerEntry>) -> Result<()> {
+    let ledger_path = directory.join(LEDGER_FILE_NAME);
+    let content = serde_json::to_string_pretty(ledger).context("failed to serialize ledger")?;
+    fs::write(&ledger_path, content).with_context(|| format!("failed to write {}", ledger_path.display()))
+}
+
+/// Outcome of a single (non-retried) download attempt.
+enum SaveAttempt {
+    Saved(String),
+    NotFound,
+    Failed(anyhow::Error),
+}
+
 /// Represents a 4chan thread
 #[derive(Debug)]
 pub struct Thread {
@@ -19,40 +77,277 @@ pub struct Thread {
 
 #[derive(Debug)]
 pub struct Link {
-    pub url:  String,
-    pub name: String,
+    pub url:           String,
+    pub name:          String,
+    pub fsize:         Option<u64>,
+    pub width:         Option<u32>,
+    pub height:        Option<u32>,
+    pub original_name: Option<String>,
+    pub md5:           Option<String>,
+}
+
+/// Criteria used to decide, from a [`Link`]'s metadata alone, whether it's
+/// worth scheduling for download. Any field left `None` imposes no
+/// restriction; a `Link` missing the corresponding metadata (e.g. size on a
+/// 4plebs regex match) always passes that check rather than being filtered
+/// out by default.
+#[derive(Debug, Default, Clone)]
+pub struct LinkFilter {
+    pub only_extensions:    Option<HashSet<String>>,
+    pub exclude_extensions: Option<HashSet<String>>,
+    pub min_size:           Option<u64>,
+    pub max_size:           Option<u64>,
+    pub min_width:          Option<u32>,
+    pub min_height:         Option<u32>,
+}
+
+impl LinkFilter {
+    /// Returns `true` if `link` satisfies every criterion set on this filter.
+    #[must_use]
+    pub fn matches(&self, link: &Link) -> bool {
+        let extension = Path::new(&link.name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase);
+
+        if let Some(only) = &self.only_extensions {
+            if !extension.as_deref().is_some_and(|ext| only.contains(ext)) {
+                return false;
+            }
+        }
+
+        if let Some(exclude) = &self.exclude_extensions {
+            if extension.as_deref().is_some_and(|ext| exclude.contains(ext)) {
+                return false;
+            }
+        }
+
+        if let Some(min_size) = self.min_size {
+            if link.fsize.is_some_and(|size| size < min_size) {
+                return false;
+            }
+        }
+
+        if let Some(max_size) = self.max_size {
+            if link.fsize.is_some_and(|size| size > max_size) {
+                return false;
+            }
+        }
+
+        if let Some(min_width) = self.min_width {
+            if link.width.is_some_and(|width| width < min_width) {
+                return false;
+            }
+        }
+
+        if let Some(min_height) = self.min_height {
+            if link.height.is_some_and(|height| height < min_height) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Parses a human-readable size such as `500K` or `4M` into a byte count.
+/// A bare number (no suffix) is taken as bytes. Suffixes are
+/// case-insensitive and binary (`K` = 1024, `M` = 1024² , `G` = 1024³).
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(chan_downloader::parse_size("500K").unwrap(), 512_000);
+/// assert_eq!(chan_downloader::parse_size("4M").unwrap(), 4 * 1024 * 1024);
+/// assert_eq!(chan_downloader::parse_size("1024").unwrap(), 1024);
+/// ```
+pub fn parse_size(input: &str) -> Result<u64> {
+    let trimmed = input.trim();
+    let last_char = trimmed.chars().last().ok_or_else(|| anyhow!("empty size string"))?;
+    let (digits, multiplier) = match last_char.to_ascii_uppercase() {
+        'K' => (&trimmed[..trimmed.len() - 1], 1024),
+        'M' => (&trimmed[..trimmed.len() - 1], 1024 * 1024),
+        'G' => (&trimmed[..trimmed.len() - 1], 1024 * 1024 * 1024),
+        _ => (trimmed, 1),
+    };
+    let value: u64 = digits.trim().parse().with_context(|| format!("invalid size: {}", input))?;
+    Ok(value * multiplier)
+}
+
+/// A single post as returned by 4chan's thread JSON API. Only the fields
+/// needed to build a [`Link`] are modeled; text-only replies simply lack
+/// `tim`/`ext` and are skipped.
+#[derive(Debug, Deserialize)]
+struct ApiPost {
+    tim:      Option<i64>,
+    ext:      Option<String>,
+    filename: Option<String>,
+    fsize:    Option<u64>,
+    w:        Option<u32>,
+    h:        Option<u32>,
+    md5:      Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiThread {
+    posts: Vec<ApiPost>,
+}
+
+/// Downloads into a `<path>.part` sibling of the destination and renames it
+/// into place once the body is fully written and (if `expected_md5` is
+/// given) verified, so a killed process or dropped connection never leaves
+/// a half-written file at `path`. A mismatched MD5 deletes the `.part` file
+/// and is reported as a failed attempt, same as any other write error.
+///
+/// The body is streamed and written chunk by chunk rather than buffered in
+/// full, which keeps peak memory low for large `.webm` files; `report` is
+/// called with `Reset` before anything else, then with the `Content-Length`
+/// once known, then with the size of each chunk as it's written, so callers
+/// can drive a progress bar without double-counting bytes from an attempt
+/// that gets retried.
+async fn save_image_once(
+    url: &str,
+    path: &str,
+    client: &Client,
+    expected_md5: Option<&str>,
+    mut report: impl FnMut(DownloadProgress),
+) -> SaveAttempt {
+    report(DownloadProgress::Reset);
+
+    let response = match client.get(url).send().await {
+        Ok(response) => response,
+        Err(err) => return SaveAttempt::Failed(err.into()),
+    };
+
+    match response.status() {
+        StatusCode::NOT_FOUND => SaveAttempt::NotFound,
+        status if status.is_success() => {
+            if let Some(length) = response.content_length() {
+                report(DownloadProgress::Length(length));
+            }
+
+            let tmp_path = format!("{}.part", path);
+            let attempt = async {
+                let mut dest =
+                    File::create(&tmp_path).with_context(|| format!("failed to create {}", tmp_path))?;
+                let mut hasher = Md5::new();
+                let mut stream = response.bytes_stream();
+                while let Some(chunk) = stream.next().await {
+                    let chunk = chunk.with_context(|| format!("failed reading body for {}", url))?;
+                    dest.write_all(&chunk).with_context(|| format!("failed to write {}", tmp_path))?;
+                    hasher.update(&chunk);
+                    report(DownloadProgress::Chunk(chunk.len()));
+                }
+
+                if let Some(expected) = expected_md5 {
+                    let digest = base64::encode(hasher.finalize());
+                    if digest != expected {
+                        return Err(anyhow!(
+                            "MD5 mismatch for {}: expected {}, got {}",
+                            path,
+                            expected,
+                            digest
+                        ));
+                    }
+                }
+
+                fs::rename(&tmp_path, path).with_context(|| format!("failed to rename {} to {}", tmp_path, path))?;
+                Ok(String::from(path))
+            }
+            .await;
+
+            match attempt {
+                Ok(saved) => SaveAttempt::Saved(saved),
+                Err(err) => {
+                    fs::remove_file(&tmp_path).ok();
+                    SaveAttempt::Failed(err)
+                },
+            }
+        },
+        status => SaveAttempt::Failed(anyhow!("Received response status: {:?}", status)),
+    }
 }
 
-/// Saves the image from the url to the given path.
-/// Returns the path on success
+/// Saves the image from the url to the given path, verifying it against
+/// `expected_md5` (a base64-encoded MD5 digest, as reported by the 4chan
+/// API) when one is given. Returns the path on success.
+///
+/// Writes atomically via a temp file plus rename (see [`save_image_once`]),
+/// and retries network errors and non-404 failure statuses up to
+/// `max_retries` times, sleeping `retry_wait` between attempts and doubling
+/// it (capped at 5 minutes) on each subsequent failure. A 404 is treated as
+/// permanent and not retried.
 ///
 /// # Examples
 ///
 /// ```
 /// use reqwest::Client;
-/// use std::{env, fs::remove_file};
+/// use std::{env, fs::remove_file, time::Duration};
 /// let client = Client::builder().user_agent("reqwest").build().unwrap();
 /// let workpath = env::current_dir().unwrap().join("1489266570954.jpg");
 /// let url = "https://i.4cdn.org/wg/1489266570954.jpg";
 /// async {
-///     let answer = chan_downloader::save_image(url, workpath.to_str().unwrap(), &client)
-///         .await
-///         .unwrap();
+///     let answer = chan_downloader::save_image(
+///         url,
+///         workpath.to_str().unwrap(),
+///         &client,
+///         None,
+///         3,
+///         Duration::from_secs(5),
+///         |_progress| {},
+///     )
+///     .await
+///     .unwrap();
 ///     assert_eq!(workpath.to_str().unwrap(), answer);
 ///     remove_file(answer).unwrap();
 /// };
 /// ```
-pub async fn save_image(url: &str, path: &str, client: &Client) -> Result<String, Error> {
+pub async fn save_image(
+    url: &str,
+    path: &str,
+    client: &Client,
+    expected_md5: Option<&str>,
+    max_retries: u32,
+    retry_wait: Duration,
+    mut report: impl FnMut(DownloadProgress),
+) -> Result<String> {
     info!(target: "image_events", "Saving image to: {}", path);
-    let response = client.get(url).send().await?;
+    let mut wait = retry_wait;
 
-    if response.status().is_success() {
-        let mut dest = File::create(path).unwrap();
-        let mut content = Cursor::new(response.bytes().await?);
-        io::copy(&mut content, &mut dest).unwrap();
+    for attempt in 0..=max_retries {
+        match save_image_once(url, path, client, expected_md5, &mut report).await {
+            SaveAttempt::Saved(saved) => {
+                info!("Saved image to: {}", saved);
+                return Ok(saved);
+            },
+            SaveAttempt::NotFound => return Err(anyhow!("File not found: {}", url)),
+            SaveAttempt::Failed(err) => {
+                if attempt == max_retries {
+                    return Err(err);
+                }
+                info!(
+                    "Attempt {}/{} to save {} failed ({}), retrying in {:?}",
+                    attempt + 1,
+                    max_retries + 1,
+                    path,
+                    err,
+                    wait
+                );
+                tokio::time::sleep(wait).await;
+                wait = std::cmp::min(wait * 2, Duration::from_secs(300));
+            },
+        }
     }
-    info!("Saved image to: {}", path);
-    Ok(String::from(path))
+
+    unreachable!("loop always returns on its last iteration")
+}
+
+/// Returns the base64-encoded MD5 digest of a file already on disk, in the
+/// same format the 4chan API reports, so it can be compared directly
+/// against a [`Link`]'s `md5` field.
+pub fn hash_file_md5(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(base64::encode(Md5::digest(&bytes)))
 }
 
 /// Returns the page content from the given url.
@@ -79,6 +374,41 @@ pub async fn get_page_content(url: &str, client: &Client) -> Result<String, Erro
     Ok(content)
 }
 
+/// Returns the ids of every thread currently alive on a board's catalog.
+///
+/// # Examples
+///
+/// ```
+/// use reqwest::Client;
+/// let client = Client::builder().user_agent("reqwest").build().unwrap();
+/// let url = "https://boards.4chan.org/wg/thread/6872254";
+/// async {
+///     match chan_downloader::get_board_threads("wg", &client).await {
+///         Ok(thread_ids) => println!("{} threads found", thread_ids.len()),
+///         Err(err) => eprintln!("Error: {}", err),
+///     }
+/// };
+/// ```
+pub async fn get_board_threads(board: &str, client: &Client) -> Result<Vec<u64>, Error> {
+    info!(target: "board_events", "Getting threads for board: {}", board);
+    let catalog_url = format!("https://a.4cdn.org/{}/catalog.json", board);
+    let response = client.get(&catalog_url).send().await?;
+    let pages: Vec<Value> = response.json().await?;
+
+    let mut thread_ids: Vec<u64> = Vec::new();
+    for page in &pages {
+        if let Some(threads) = page["threads"].as_array() {
+            for thread in threads {
+                if let Some(no) = thread["no"].as_u64() {
+                    thread_ids.push(no);
+                }
+            }
+        }
+    }
+    info!("Got {} threads from board {}", thread_ids.len(), board);
+    Ok(thread_ids)
+}
+
 /// Returns the board name and thread id.
 ///
 /// # Examples
@@ -144,13 +474,64 @@ pub fn get_image_links(page_content: &str) -> Vec<Link> {
     let mut links_v: Vec<Link> = Vec::new();
     for cap in links_iter.step_by(2) {
         links_v.push(Link {
-            url:  String::from(&cap[1]),
-            name: String::from(&cap[2]),
+            url:           String::from(&cap[1]),
+            name:          String::from(&cap[2]),
+            fsize:         None,
+            width:         None,
+            height:        None,
+            original_name: None,
+            md5:           None,
         });
     }
     links_v
 }
 
+/// Returns the image links and metadata for a thread via 4chan's JSON API.
+/// Unlike [`get_image_links`], this carries structured per-file metadata
+/// (size, dimensions, original filename, MD5) straight from the API, with
+/// no doubled-match regex workaround needed.
+///
+/// # Examples
+///
+/// ```
+/// use reqwest::Client;
+/// let client = Client::builder().user_agent("reqwest").build().unwrap();
+/// async {
+///     match chan_downloader::get_image_links_from_api("wg", 6872254, &client).await {
+///         Ok(links) => {
+///             for link in links {
+///                 println!("{} and {}", link.name, link.url);
+///             }
+///         },
+///         Err(err) => eprintln!("Error: {}", err),
+///     }
+/// };
+/// ```
+pub async fn get_image_links_from_api(board: &str, thread_id: u32, client: &Client) -> Result<Vec<Link>, Error> {
+    info!(target: "link_events", "Getting image links from API for {}/{}", board, thread_id);
+    let api_url = format!("https://a.4cdn.org/{}/thread/{}.json", board, thread_id);
+    let response = client.get(&api_url).send().await?;
+    let thread: ApiThread = response.json().await?;
+
+    let mut links_v: Vec<Link> = Vec::new();
+    for post in thread.posts {
+        if let (Some(tim), Some(ext)) = (post.tim, post.ext) {
+            let name = format!("{}{}", tim, ext);
+            links_v.push(Link {
+                url:           format!("//i.4cdn.org/{}/{}", board, name),
+                name:          name.clone(),
+                fsize:         post.fsize,
+                width:         post.w,
+                height:        post.h,
+                original_name: post.filename.map(|filename| format!("{}{}", filename, ext)),
+                md5:           post.md5,
+            });
+        }
+    }
+    info!("Got {} image links from API for {}/{}", links_v.len(), board, thread_id);
+    Ok(links_v)
+}
+
 /// Initialize a [`Regex`] once
 #[macro_export]
 macro_rules! regex {
@@ -165,6 +546,31 @@ mod tests {
     use super::*;
     use reqwest::Client;
 
+    #[test]
+    fn it_round_trips_the_ledger() {
+        let directory = std::env::temp_dir().join(format!("chan-downloader-test-ledger-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        assert!(load_ledger(&directory).unwrap().is_empty());
+
+        let mut ledger = HashMap::new();
+        ledger.insert(
+            String::from("/downloads/wg/1489266570954.jpg"),
+            LedgerEntry {
+                size: 1234,
+                md5:  Some(String::from("deadbeef")),
+            },
+        );
+        save_ledger(&directory, &ledger).unwrap();
+
+        let reloaded = load_ledger(&directory).unwrap();
+        let entry = &reloaded["/downloads/wg/1489266570954.jpg"];
+        assert_eq!(entry.size, 1234);
+        assert_eq!(entry.md5.as_deref(), Some("deadbeef"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
     #[test]
     fn it_gets_4chan_thread_info() {
         let url = "https://boards.4chan.org/wg/thread/6872254";
@@ -209,6 +615,63 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_parses_human_sizes() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("500k").unwrap(), 512_000);
+        assert_eq!(parse_size("4M").unwrap(), 4 * 1024 * 1024);
+        assert_eq!(parse_size("1G").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("").is_err());
+    }
+
+    #[test]
+    fn it_filters_links() {
+        let link = Link {
+            url:           String::from("//i.4cdn.org/wg/1489266570954.webm"),
+            name:          String::from("1489266570954.webm"),
+            fsize:         Some(2_000_000),
+            width:         Some(1920),
+            height:        Some(1080),
+            original_name: None,
+            md5:           None,
+        };
+
+        let only_webm = LinkFilter {
+            only_extensions: Some(["webm".to_owned()].into_iter().collect()),
+            ..LinkFilter::default()
+        };
+        assert!(only_webm.matches(&link));
+
+        let only_jpg = LinkFilter {
+            only_extensions: Some(["jpg".to_owned()].into_iter().collect()),
+            ..LinkFilter::default()
+        };
+        assert!(!only_jpg.matches(&link));
+
+        let too_small = LinkFilter {
+            min_size: Some(3_000_000),
+            ..LinkFilter::default()
+        };
+        assert!(!too_small.matches(&link));
+
+        let wallpaper_sized = LinkFilter {
+            min_width:  Some(1920),
+            min_height: Some(1080),
+            ..LinkFilter::default()
+        };
+        assert!(wallpaper_sized.matches(&link));
+    }
+
+    #[tokio::test]
+    async fn it_gets_4chan_image_links_from_api() {
+        let client = Client::builder().user_agent("reqwest").build().unwrap();
+        let links = get_image_links_from_api("wg", 6872254, &client).await.unwrap();
+        for link in links {
+            assert!(link.url.starts_with("//i.4cdn.org/wg/"));
+            assert!(link.md5.is_some());
+        }
+    }
+
     #[tokio::test]
     async fn it_gets_page_content() {
         let client = Client::builder().user_agent("reqwest").build().unwrap();
@@ -223,9 +686,17 @@ mod tests {
         let client = Client::builder().user_agent("reqwest").build().unwrap();
         let workpath = env::current_dir().unwrap().join("1489266570954.jpg");
         let url = "https://i.4cdn.org/wg/1489266570954.jpg";
-        let answer = save_image(url, workpath.to_str().unwrap(), &client)
-            .await
-            .unwrap();
+        let answer = save_image(
+            url,
+            workpath.to_str().unwrap(),
+            &client,
+            None,
+            3,
+            std::time::Duration::from_secs(5),
+            |_progress| {},
+        )
+        .await
+        .unwrap();
         assert_eq!(workpath.to_str().unwrap(), answer);
         fs::remove_file(answer).unwrap();
     }
@@ -236,9 +707,17 @@ mod tests {
         let client = Client::builder().user_agent("reqwest").build().unwrap();
         let workpath = env::current_dir().unwrap().join("1614942709612.jpg");
         let url = "https://img.4plebs.org/boards/x/image/1614/94/1614942709612.jpg";
-        let answer = save_image(url, workpath.to_str().unwrap(), &client)
-            .await
-            .unwrap();
+        let answer = save_image(
+            url,
+            workpath.to_str().unwrap(),
+            &client,
+            None,
+            3,
+            std::time::Duration::from_secs(5),
+            |_progress| {},
+        )
+        .await
+        .unwrap();
         assert_eq!(workpath.to_str().unwrap(), answer);
         fs::remove_file(answer).unwrap();
     }